@@ -25,8 +25,26 @@
     unused_qualifications
 )]
 
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
 use async_session::{async_trait, Session, SessionStore};
-use redis::{aio::Connection, AsyncCommands, Client, IntoConnectionInfo, RedisResult};
+use once_cell::sync::OnceCell;
+use redis::{
+    aio::ConnectionManager, AsyncCommands, Client, ExistenceCheck, IntoConnectionInfo,
+    RedisResult, ScanOptions, SetExpiry, SetOptions,
+};
+use std::time::Duration;
+
+/// the length in bytes of the randomly-generated nonce prepended to
+/// each encrypted session payload
+const NONCE_LEN: usize = 12;
+
+/// the COUNT hint passed to each SCAN call, so that each round trip
+/// examines roughly this many keys instead of redis's default COUNT
+/// of 10
+const SCAN_COUNT: usize = 100;
 
 /// Errors that can arise in the operation of the session stores
 /// included in this crate
@@ -44,13 +62,79 @@ pub enum Error {
     /// an error that comes from base64
     #[error(transparent)]
     Base64(#[from] base64::DecodeError),
+
+    /// an error that comes from bincode
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+
+    /// the stored payload could not be decrypted, either because it
+    /// was written with a different encryption key, was moved from a
+    /// different session's redis key, or was never encrypted to
+    /// begin with
+    #[error("failed to decrypt session payload")]
+    Decryption,
+
+    /// the session payload was too large for AES-256-GCM to encrypt
+    #[error("failed to encrypt session payload")]
+    Encryption,
+}
+
+/// The wire format used to encode session records before they are
+/// written to redis.
+///
+/// Defaults to [`Serializer::Json`] for backward compatibility. Set
+/// with [`RedisSessionStore::with_serializer`].
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub enum Serializer {
+    /// encode sessions with [`serde_json`]
+    #[default]
+    Json,
+
+    /// encode sessions with [`bincode`], which produces a more
+    /// compact representation at the cost of human readability
+    Bincode,
+}
+
+impl Serializer {
+    fn serialize(self, session: &Session) -> Result<Vec<u8>, Error> {
+        match self {
+            Serializer::Json => Ok(serde_json::to_vec(session)?),
+            Serializer::Bincode => Ok(bincode::serialize(session)?),
+        }
+    }
+
+    fn deserialize(self, bytes: &[u8]) -> Result<Session, Error> {
+        match self {
+            Serializer::Json => Ok(serde_json::from_slice(bytes)?),
+            Serializer::Bincode => Ok(bincode::deserialize(bytes)?),
+        }
+    }
 }
 
 /// # RedisSessionStore
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct RedisSessionStore {
     client: Client,
+    connection: OnceCell<ConnectionManager>,
     prefix: Option<String>,
+    serializer: Serializer,
+    encryption_key: Option<[u8; 32]>,
+    session_id_rotation: bool,
+    default_ttl: Option<Duration>,
+}
+
+impl std::fmt::Debug for RedisSessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisSessionStore")
+            .field("client", &self.client)
+            .field("prefix", &self.prefix)
+            .field("serializer", &self.serializer)
+            .field("encryption_key", &self.encryption_key.map(|_| "..."))
+            .field("session_id_rotation", &self.session_id_rotation)
+            .field("default_ttl", &self.default_ttl)
+            .finish()
+    }
 }
 
 impl RedisSessionStore {
@@ -63,7 +147,12 @@ impl RedisSessionStore {
     pub fn from_client(client: Client) -> Self {
         Self {
             client,
+            connection: OnceCell::new(),
             prefix: None,
+            serializer: Serializer::default(),
+            encryption_key: None,
+            session_id_rotation: false,
+            default_ttl: None,
         }
     }
 
@@ -95,8 +184,142 @@ impl RedisSessionStore {
         self
     }
 
+    /// sets the [`Serializer`] used to encode and decode session
+    /// records, defaulting to [`Serializer::Json`]
+    ///
+    /// ```rust
+    /// # use async_redis_session::{RedisSessionStore, Serializer};
+    /// let store = RedisSessionStore::new("redis://127.0.0.1").unwrap()
+    ///     .with_serializer(Serializer::Bincode);
+    /// ```
+    ///
+    /// Note that changing the serializer for a store that already has
+    /// sessions written with a different serializer will make those
+    /// existing sessions unreadable.
+    pub fn with_serializer(mut self, serializer: Serializer) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    /// enables encryption of session payloads at rest, using
+    /// AES-256-GCM with `key`
+    ///
+    /// The session id/key is left in plaintext so that TTL and
+    /// `SCAN`-based enumeration continue to work; only the stored
+    /// value is encrypted.
+    ///
+    /// ```rust
+    /// # use async_redis_session::RedisSessionStore;
+    /// let store = RedisSessionStore::new("redis://127.0.0.1").unwrap()
+    ///     .with_encryption_key([0u8; 32]);
+    /// ```
+    pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// when enabled, `load_session` regenerates the session's id on
+    /// every successful load, defending against session fixation
+    ///
+    /// The record is moved to the new key, preserving the remaining
+    /// TTL, and the old key is removed. The returned [`Session`]
+    /// carries a fresh id, so the caller re-sets the session cookie.
+    ///
+    /// ```rust
+    /// # use async_redis_session::RedisSessionStore;
+    /// let store = RedisSessionStore::new("redis://127.0.0.1").unwrap()
+    ///     .with_session_id_rotation(true);
+    /// ```
+    pub fn with_session_id_rotation(mut self, rotate: bool) -> Self {
+        self.session_id_rotation = rotate;
+        self
+    }
+
+    /// sets a default ttl applied to sessions that do not have an
+    /// explicit expiry set with [`Session::expire_in`]
+    ///
+    /// Without this, sessions created with no expiry are stored with
+    /// a plain `SET` and live in redis forever. When a default ttl is
+    /// configured, such sessions are stored with `SET ... EX` instead,
+    /// and later updates to the same session preserve the
+    /// server-side countdown with `SET ... KEEPTTL` rather than
+    /// resetting it.
+    ///
+    /// ```rust
+    /// # use async_redis_session::RedisSessionStore;
+    /// # use std::time::Duration;
+    /// let store = RedisSessionStore::new("redis://127.0.0.1").unwrap()
+    ///     .with_default_ttl(Duration::from_secs(60 * 60 * 24));
+    /// ```
+    pub fn with_default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.default_ttl = Some(default_ttl);
+        self
+    }
+
+    /// encrypts `bytes` for storage under the (prefixed) redis `key`, binding
+    /// the ciphertext to that key as AAD so it cannot be replayed under a
+    /// different key
+    fn encrypt(&self, key: &str, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self.encryption_key {
+            None => Ok(bytes),
+            Some(encryption_key) => {
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&encryption_key));
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let mut payload = cipher
+                    .encrypt(
+                        &nonce,
+                        Payload {
+                            msg: &bytes,
+                            aad: key.as_bytes(),
+                        },
+                    )
+                    .map_err(|_| Error::Encryption)?;
+                let mut out = nonce.to_vec();
+                out.append(&mut payload);
+                Ok(out)
+            }
+        }
+    }
+
+    /// decrypts a payload previously produced by [`Self::encrypt`], verifying
+    /// that it was encrypted for this same (prefixed) redis `key`
+    fn decrypt(&self, key: &str, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self.encryption_key {
+            None => Ok(bytes),
+            Some(encryption_key) => {
+                if bytes.len() < NONCE_LEN {
+                    return Err(Error::Decryption);
+                }
+                let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&encryption_key));
+                cipher
+                    .decrypt(
+                        Nonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad: key.as_bytes(),
+                        },
+                    )
+                    .map_err(|_| Error::Decryption)
+            }
+        }
+    }
+
     async fn ids(&self) -> Result<Vec<String>, Error> {
-        Ok(self.connection().await?.keys(self.prefix_key("*")).await?)
+        let mut connection = self.connection().await?;
+        let mut iter: redis::AsyncIter<'_, String> = connection
+            .scan_options(
+                ScanOptions::default()
+                    .with_pattern(self.prefix_key("*"))
+                    .with_count(SCAN_COUNT),
+            )
+            .await?;
+
+        let mut ids = Vec::new();
+        while let Some(id) = iter.next_item().await {
+            ids.push(id);
+        }
+        Ok(ids)
     }
 
     /// returns the number of sessions in this store
@@ -127,8 +350,14 @@ impl RedisSessionStore {
         }
     }
 
-    async fn connection(&self) -> RedisResult<Connection> {
-        self.client.get_async_connection().await
+    async fn connection(&self) -> RedisResult<ConnectionManager> {
+        if let Some(connection) = self.connection.get() {
+            Ok(connection.clone())
+        } else {
+            let connection = ConnectionManager::new(self.client.clone()).await?;
+            // if another task won the race to populate the cell, fall back to its copy
+            Ok(self.connection.get_or_init(|| connection).clone())
+        }
     }
 }
 
@@ -138,27 +367,69 @@ impl SessionStore for RedisSessionStore {
 
     async fn load_session(&self, cookie_value: &str) -> Result<Option<Session>, Self::Error> {
         let id = Session::id_from_cookie_value(cookie_value)?;
+        let key = self.prefix_key(&id);
         let mut connection = self.connection().await?;
-        let record: Option<String> = connection.get(self.prefix_key(id)).await?;
-        match record {
-            Some(value) => Ok(serde_json::from_str(&value)?),
-            None => Ok(None),
+        let record: Option<Vec<u8>> = connection.get(&key).await?;
+        let bytes = match record {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        let bytes = self.decrypt(&key, bytes)?;
+        let mut session = self.serializer.deserialize(&bytes)?;
+
+        if self.session_id_rotation {
+            let ttl: i64 = connection.ttl(&key).await?;
+            // ttl >= 0: a real remaining lifetime, carry it over (clamped to
+            // at least a second, since TTL rounds down to 0 for a live key
+            // with sub-second time left, and `SET ... EX 0` is invalid).
+            // ttl == -1: the key has no expiry at all.
+            // ttl <= -2: the key is already gone (expired/evicted between our
+            // GET and this TTL call) -- don't immortalize a fresh copy of it.
+            if ttl > -2 {
+                session.regenerate();
+                let new_key = self.prefix_key(session.id());
+                let new_bytes = self.encrypt(&new_key, self.serializer.serialize(&session)?)?;
+
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+                if ttl >= 0 {
+                    pipe.set_ex(&new_key, new_bytes, ttl.max(1) as u64).ignore();
+                } else {
+                    pipe.set(&new_key, new_bytes).ignore();
+                }
+                pipe.del(&key).ignore();
+                let _: () = pipe.query_async(&mut connection).await?;
+            }
         }
+
+        Ok(Some(session))
     }
 
     async fn store_session(&self, session: &mut Session) -> Result<Option<String>, Self::Error> {
         let id = self.prefix_key(session.id());
-        let string = serde_json::to_string(&session)?;
+        let bytes = self.encrypt(&id, self.serializer.serialize(session)?)?;
 
         let mut connection = self.connection().await?;
 
-        match session.expires_in() {
-            None => connection.set(id, string).await?,
+        match (session.expires_in(), self.default_ttl) {
+            (Some(expiry), _) => connection.set_ex(id, bytes, expiry.as_secs()).await?,
+
+            (None, None) => connection.set(id, bytes).await?,
+
+            (None, Some(default_ttl)) => {
+                // first assume this key already exists and try to preserve its ttl; if it
+                // didn't exist, fall back to establishing the default ttl for this new key
+                let keep_ttl = SetOptions::default()
+                    .conditional_set(ExistenceCheck::XX)
+                    .with_expiration(SetExpiry::KEEPTTL);
+                let updated: Option<()> = connection.set_options(&id, &bytes, keep_ttl).await?;
 
-            Some(expiry) => {
-                connection
-                    .set_ex(id, string, expiry.as_secs() as usize)
-                    .await?
+                if updated.is_none() {
+                    let with_default_ttl = SetOptions::default()
+                        .with_expiration(SetExpiry::EX(default_ttl.as_secs()));
+                    connection.set_options(id, bytes, with_default_ttl).await?
+                }
             }
         };
 
@@ -178,9 +449,25 @@ impl SessionStore for RedisSessionStore {
         if self.prefix.is_none() {
             let _: () = redis::cmd("FLUSHDB").query_async(&mut connection).await?;
         } else {
-            let ids = self.ids().await?;
-            if !ids.is_empty() {
-                connection.del(ids).await?;
+            const DEL_BATCH_SIZE: usize = 100;
+            let mut delete_connection = connection.clone();
+            let mut iter: redis::AsyncIter<'_, String> = connection
+                .scan_options(
+                    ScanOptions::default()
+                        .with_pattern(self.prefix_key("*"))
+                        .with_count(SCAN_COUNT),
+                )
+                .await?;
+
+            let mut batch = Vec::with_capacity(DEL_BATCH_SIZE);
+            while let Some(id) = iter.next_item().await {
+                batch.push(id);
+                if batch.len() == DEL_BATCH_SIZE {
+                    delete_connection.del(std::mem::take(&mut batch)).await?;
+                }
+            }
+            if !batch.is_empty() {
+                delete_connection.del(batch).await?;
             }
         }
         Ok(())
@@ -359,4 +646,118 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn encryption_round_trip() -> Result<(), Error> {
+        test_store().await; // clear the db
+
+        let store = RedisSessionStore::new("redis://127.0.0.1")?.with_encryption_key([0u8; 32]);
+        store.clear_store().await?;
+
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.store_session(&mut session).await?.unwrap();
+
+        let loaded_session = store.load_session(&cookie_value).await?.unwrap();
+        assert_eq!("value", &loaded_session.get::<String>("key").unwrap());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn decrypting_a_payload_moved_to_a_foreign_key_fails() -> Result<(), Error> {
+        test_store().await; // clear the db
+
+        let store = RedisSessionStore::new("redis://127.0.0.1")?.with_encryption_key([0u8; 32]);
+        store.clear_store().await?;
+
+        let plaintext = b"hello".to_vec();
+        let ciphertext = store.encrypt("key-a", plaintext.clone())?;
+
+        // decrypting under the key it was encrypted for succeeds
+        assert_eq!(plaintext, store.decrypt("key-a", ciphertext.clone())?);
+
+        // the same ciphertext copied onto a different key is rejected,
+        // rather than silently authenticating under the wrong identity
+        assert!(matches!(
+            store.decrypt("key-b", ciphertext),
+            Err(Error::Decryption)
+        ));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn bincode_round_trip() -> Result<(), Error> {
+        test_store().await; // clear the db
+
+        let store =
+            RedisSessionStore::new("redis://127.0.0.1")?.with_serializer(Serializer::Bincode);
+        store.clear_store().await?;
+
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.store_session(&mut session).await?.unwrap();
+
+        let loaded_session = store.load_session(&cookie_value).await?.unwrap();
+        assert_eq!("value", &loaded_session.get::<String>("key").unwrap());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn session_id_rotation_moves_the_record_and_keeps_the_ttl() -> Result<(), Error> {
+        test_store().await; // clear the db
+
+        let store = RedisSessionStore::new("redis://127.0.0.1")?.with_session_id_rotation(true);
+        store.clear_store().await?;
+
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        session.insert("key", "value")?;
+        let cookie_value = store.store_session(&mut session).await?.unwrap();
+        let old_id = session.id().to_owned();
+
+        let rotated = store.load_session(&cookie_value).await?.unwrap();
+        assert_ne!(old_id, rotated.id());
+        assert_eq!("value", &rotated.get::<String>("key").unwrap());
+
+        let ids = store.ids().await?;
+        assert!(!ids.contains(&old_id));
+        assert!(ids.contains(&rotated.id().to_string()));
+
+        let ttl = store.ttl_for_session(&rotated).await?;
+        assert!(ttl > 55 && ttl <= 60);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn default_ttl_is_set_on_create_and_kept_on_update() -> Result<(), Error> {
+        test_store().await; // clear the db
+
+        let store = RedisSessionStore::new("redis://127.0.0.1")?
+            .with_default_ttl(Duration::from_secs(60));
+        store.clear_store().await?;
+
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.store_session(&mut session).await?.unwrap();
+
+        let ttl = store.ttl_for_session(&session).await?;
+        assert!(ttl > 55 && ttl <= 60);
+
+        let mut session = store.load_session(&cookie_value).await?.unwrap();
+        session.insert("key", "other value")?;
+        store.store_session(&mut session).await?;
+
+        // updating the session did not reset the countdown established on create
+        let ttl = store.ttl_for_session(&session).await?;
+        assert!(ttl > 55 && ttl <= 60);
+
+        let session = store.load_session(&cookie_value).await?.unwrap();
+        assert_eq!("other value", &session.get::<String>("key").unwrap());
+
+        Ok(())
+    }
 }